@@ -1,12 +1,11 @@
 use misc::{pretty_print_packages, Errors};
 use owo_colors::OwoColorize;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::PathBuf, vec};
 
 mod misc;
 pub mod remote;
-mod url;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct Package {
@@ -19,8 +18,34 @@ pub struct Package {
     pub target: Option<String>,
     pub version_req: Option<String>,
     pub bins: Vec<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub source_path: Option<String>,
+    #[serde(default)]
+    pub source: Source,
+}
+
+/// Where a package was originally installed from.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Default)]
+pub enum Source {
+    /// A crate pulled from a registry such as crates.io.
+    #[default]
+    Registry,
+    /// A crate installed from a local directory (`cargo install --path`).
+    Path(String),
+    /// A crate installed from a git repository (`cargo install --git`).
+    Git { url: String, reference: GitRef },
+}
+
+/// The git reference a git-sourced package is pinned to.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Default)]
+pub enum GitRef {
+    /// A locked commit hash.
+    Rev(String),
+    /// A named branch.
+    Branch(String),
+    /// A named tag.
+    Tag(String),
+    /// The repository's default branch.
+    #[default]
+    Default,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,6 +53,14 @@ struct Crates {
     installs: HashMap<String, Install>,
 }
 
+/// The legacy `.crates.toml` (v1) layout: a `[v1]` table mapping
+/// `"name version (source)"` keys to their installed binary names.
+#[derive(Serialize, Deserialize, Debug)]
+struct CratesV1 {
+    #[serde(default)]
+    v1: HashMap<String, Vec<String>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Install {
     #[serde(default)]
@@ -42,7 +75,7 @@ struct Install {
     pub bins: Vec<String>,
 }
 
-/// Returns the path to the .crates2.json file.
+/// Returns the path to the .crates2.json (v2) file.
 fn get_crates_path() -> PathBuf {
     #[cfg(test)]
     {
@@ -52,13 +85,30 @@ fn get_crates_path() -> PathBuf {
 
     #[cfg(not(test))]
     {
-        let path = dirs::home_dir().unwrap().join(".cargo/.crates2.json");
-        assert!(path.exists());
-        path
+        dirs::home_dir().unwrap().join(".cargo/.crates2.json")
     }
 }
 
-/// Gets the currently installed packages from the .crates2.json file.
+/// Returns the path to the legacy .crates.toml (v1) file.
+fn get_crates_v1_path() -> PathBuf {
+    #[cfg(test)]
+    {
+        use std::env;
+        env::current_dir().unwrap().join("tests/.crates.toml")
+    }
+
+    #[cfg(not(test))]
+    {
+        dirs::home_dir().unwrap().join(".cargo/.crates.toml")
+    }
+}
+
+/// Gets the currently installed packages from cargo's tracking files.
+///
+/// The newer `.crates2.json` (v2) is preferred for its richer metadata, but
+/// when it is absent or corrupt the older `.crates.toml` (v1) is used as a
+/// fallback. When both exist they are merged, v2 winning on metadata while v1
+/// fills in any packages or binaries v2 does not record.
 ///
 /// # Examples
 /// ```no_run
@@ -68,39 +118,102 @@ fn get_crates_path() -> PathBuf {
 /// ```
 ///
 /// # Panics
-/// * If the .crates2.json file is not valid JSON.
-/// * If the .crates2.json file cannot be read.
+/// * If neither tracking file can be read.
 pub fn get_packages() -> Vec<Package> {
-    let path = get_crates_path();
-    let crates: Crates = serde_json::from_str(
-        &std::fs::read_to_string(path).unwrap_or_else(|_| panic!("{}", Errors::ReadFile)),
-    )
-    .unwrap_or_else(|_| panic!("{}", Errors::JsonParse));
+    match (get_packages_v2(), get_packages_v1()) {
+        (Some(v2), Some(v1)) => merge_packages(v2, v1),
+        (Some(v2), None) => v2,
+        (None, Some(v1)) => v1,
+        (None, None) => panic!("{}", Errors::ReadFile),
+    }
+}
 
-    let mut packages = vec![];
+/// Reads the packages recorded in `.crates2.json`, or `None` if it is missing
+/// or not valid JSON.
+fn get_packages_v2() -> Option<Vec<Package>> {
+    let contents = std::fs::read_to_string(get_crates_path()).ok()?;
+    let crates: Crates = match serde_json::from_str(&contents) {
+        Ok(crates) => crates,
+        Err(_) => {
+            eprintln!("{}", Errors::JsonParse);
+            return None;
+        }
+    };
 
-    for (id, install) in crates.installs {
-        let (name, version, is_git, source_path) = slice_info(&id);
+    let packages = crates
+        .installs
+        .into_iter()
+        .map(|(id, install)| {
+            let (name, version, source) = slice_info(&id);
+
+            Package {
+                name,
+                features: install.features,
+                all_features: install.all_features,
+                no_default_features: install.no_default_features,
+                version,
+                profile: install.profile,
+                target: install.target,
+                version_req: install.version_req,
+                bins: install.bins,
+                source,
+            }
+        })
+        .collect();
 
-        if is_git {
-            continue;
-        }
+    Some(packages)
+}
+
+/// Reads the packages recorded in the legacy `.crates.toml`, or `None` if it is
+/// missing or not valid TOML. Feature, profile and target information defaults
+/// since v1 does not record it.
+fn get_packages_v1() -> Option<Vec<Package>> {
+    let contents = std::fs::read_to_string(get_crates_v1_path()).ok()?;
+    let crates: CratesV1 = toml::from_str(&contents).ok()?;
+
+    let packages = crates
+        .v1
+        .into_iter()
+        .map(|(id, bins)| {
+            let (name, version, source) = slice_info(&id);
+
+            Package {
+                name,
+                features: vec![],
+                all_features: false,
+                no_default_features: false,
+                version,
+                profile: "release".to_string(),
+                target: None,
+                version_req: None,
+                bins,
+                source,
+            }
+        })
+        .collect();
+
+    Some(packages)
+}
 
-        packages.push(Package {
-            name: name.to_string(),
-            features: install.features,
-            all_features: install.all_features,
-            no_default_features: install.no_default_features,
-            version,
-            profile: install.profile,
-            target: install.target,
-            version_req: install.version_req,
-            bins: install.bins,
-            source_path,
-        });
+/// Merges v1 packages into the richer v2 set, preferring v2's metadata and only
+/// filling in packages or binaries that v2 is missing.
+fn merge_packages(v2: Vec<Package>, v1: Vec<Package>) -> Vec<Package> {
+    let mut merged = v2;
+
+    for package in v1 {
+        match merged.iter_mut().find(|p| p.name == package.name) {
+            Some(existing) => {
+                for bin in package.bins {
+                    if !existing.bins.contains(&bin) {
+                        existing.bins.push(bin);
+                    }
+                }
+            }
+            None => merged.push(package),
+        }
     }
 
-    packages
+    merged
 }
 
 pub fn install_packages(
@@ -109,18 +222,31 @@ pub fn install_packages(
     skip_update: bool,
     skip_remove: bool,
     yes: bool,
+    target_dir: Option<PathBuf>,
+    force: bool,
 ) {
     let installed_packages = get_packages();
 
+    // Share a single target directory across the whole batch so dependency
+    // builds are cached between crates. An explicit flag wins; otherwise honor
+    // a target directory already set in the environment.
+    #[cfg_attr(test, allow(unused_variables))]
+    let target_dir = target_dir.or_else(|| {
+        std::env::var_os("CARGO_TARGET_DIR")
+            .or_else(|| std::env::var_os("CARGO_BUILD_TARGET_DIR"))
+            .map(PathBuf::from)
+    });
+
     let mut to_update: Vec<Package> = vec![];
     let mut to_install: Vec<Package> = vec![];
     let mut to_remove: Vec<Package> = vec![];
+    let mut to_reconfigure: Vec<Package> = vec![];
 
     if !skip_install {
         for package in packages {
             let installed = installed_packages.iter().find(|p| p.name == package.name);
 
-            if installed.is_none() {
+            if force || installed.is_none() {
                 to_install.push(package.clone());
             } else if let Some(installed_pkg) = installed {
                 if !check_bins_installed(&installed_pkg.bins) {
@@ -135,11 +261,25 @@ pub fn install_packages(
         }
     }
 
-    if !skip_update {
+    if !skip_update && !force {
         for package in &installed_packages {
             if let Some(p) = packages.iter().find(|np| np.name == package.name) {
-                if p.version > package.version {
+                // Prefer the backup's version requirement: a package is up to
+                // date while the installed version still satisfies it. Only
+                // fall back to an exact-version comparison when no requirement
+                // was recorded.
+                let up_to_date = match p.version_req.as_deref().and_then(|r| VersionReq::parse(r).ok())
+                {
+                    Some(req) => req.matches(&package.version),
+                    None => p.version <= package.version,
+                };
+
+                if !up_to_date {
                     to_update.push(p.clone());
+                } else if config_differs(p, package) {
+                    // Up to date, but the backup was built with different
+                    // features/profile/target, so the binary needs rebuilding.
+                    to_reconfigure.push(p.clone());
                 }
             }
         }
@@ -153,9 +293,23 @@ pub fn install_packages(
         }
     }
 
-    pretty_print_packages(&to_install.clone(), &to_update.clone(), &to_remove.clone());
+    // Bump any registry packages recorded at a now-yanked version before we
+    // print the plan or generate install commands.
+    #[allow(unused_mut)]
+    let mut bumped: Vec<remote::YankBump> = vec![];
+    #[cfg(not(test))]
+    {
+        bumped.extend(remote::resolve_yanked(&mut to_install));
+        bumped.extend(remote::resolve_yanked(&mut to_update));
+    }
+
+    pretty_print_packages(&to_install, &to_update, &to_remove, &to_reconfigure, &bumped);
 
-    if to_install.is_empty() && to_update.is_empty() && to_remove.is_empty() {
+    if to_install.is_empty()
+        && to_update.is_empty()
+        && to_remove.is_empty()
+        && to_reconfigure.is_empty()
+    {
         println!("{}", "No packages to install, update, or remove. Your system is already in sync with the backup.".green());
         return;
     }
@@ -170,20 +324,36 @@ pub fn install_packages(
             // TODO: Install
 
             for package in to_install {
-                execute_cmd(&package, CommandType::Install);
+                execute_cmd(&package, CommandType::Install, target_dir.as_deref(), force);
             }
 
             for package in to_update {
-                execute_cmd(&package, CommandType::Install);
+                execute_cmd(&package, CommandType::Install, target_dir.as_deref(), force);
+            }
+
+            for package in to_reconfigure {
+                // A same-version rebuild always needs --force to overwrite the
+                // existing binary.
+                execute_cmd(&package, CommandType::Install, target_dir.as_deref(), true);
             }
 
             for package in to_remove {
-                execute_cmd(&package, CommandType::Remove);
+                execute_cmd(&package, CommandType::Remove, target_dir.as_deref(), force);
             }
         }
     }
 }
 
+/// Returns true when the backup's build configuration diverges from what is
+/// currently installed, so the package needs rebuilding even at the same version.
+fn config_differs(backup: &Package, installed: &Package) -> bool {
+    backup.features != installed.features
+        || backup.all_features != installed.all_features
+        || backup.no_default_features != installed.no_default_features
+        || backup.profile != installed.profile
+        || backup.target != installed.target
+}
+
 /// Checks if the binaries for a package are actually installed.
 fn check_bins_installed(bins: &[String]) -> bool {
     if bins.is_empty() {
@@ -198,51 +368,122 @@ fn check_bins_installed(bins: &[String]) -> bool {
     })
 }
 
-/// Gets the Package name and Version from the string.
-/// Returns (name, version, is_git_package, source_path)
+/// Gets the Package name, Version and [`Source`] from the string.
+///
+/// A git source is encoded as `git+<url>[?<ref>]#<locked-commit>`; the part
+/// before `#` (sans the optional `?branch=`/`?tag=`/`?rev=` query) is the
+/// repository URL and the 40-char hex after `#` is the locked commit.
 ///
 /// # Examples
 /// ```no_run
-/// let (name, version, is_git, path) = slice_info("foo 0.1.0 (path+file:///home/user/foo)");
+/// let (name, version, source) = slice_info("foo 0.1.0 (path+file:///home/user/foo)");
 /// ```
-fn slice_info(package_str: &str) -> (String, Version, bool, Option<String>) {
+fn slice_info(package_str: &str) -> (String, Version, Source) {
     let splits: Vec<&str> = package_str.splitn(3, ' ').collect();
     let name = splits[0].to_string();
     let version = Version::parse(splits[1]).unwrap();
     let url = splits[2].trim_start_matches('(').trim_end_matches(')');
 
-    let is_git_package = url.starts_with("git+");
-
-    let source_path = if url.starts_with("path+file://") {
-        url.strip_prefix("path+file://").map(|s| s.to_string())
+    let source = if let Some(git) = url.strip_prefix("git+") {
+        let (locator, locked) = match git.split_once('#') {
+            Some((locator, hash)) => (locator, Some(hash.to_string())),
+            None => (git, None),
+        };
+        let (repo, query) = match locator.split_once('?') {
+            Some((repo, query)) => (repo, Some(query)),
+            None => (locator, None),
+        };
+        // The locked commit after `#` wins: it is the exact revision the backed
+        // up binary was built from, so pinning to it round-trips the install
+        // regardless of the `?branch=`/`?tag=`/`?rev=` hint cargo also records.
+        // Fall back to the named reference only when no commit was locked.
+        let reference = match locked {
+            Some(hash) => GitRef::Rev(hash),
+            None => match query {
+                Some(q) if q.starts_with("branch=") => {
+                    GitRef::Branch(q["branch=".len()..].to_string())
+                }
+                Some(q) if q.starts_with("tag=") => GitRef::Tag(q["tag=".len()..].to_string()),
+                Some(q) if q.starts_with("rev=") => GitRef::Rev(q["rev=".len()..].to_string()),
+                _ => GitRef::Default,
+            },
+        };
+        Source::Git {
+            url: repo.to_string(),
+            reference,
+        }
+    } else if let Some(path) = url.strip_prefix("path+file://") {
+        Source::Path(path.to_string())
     } else {
-        None
+        Source::Registry
     };
 
-    (name, version, is_git_package, source_path)
+    (name, version, source)
 }
 
 #[test]
 fn test_slice_info() {
     use std::str::FromStr;
 
-    let (name, version, is_git, path) = slice_info("foo 0.1.0 (path+file:///home/user/foo)");
+    let (name, version, source) = slice_info("foo 0.1.0 (path+file:///home/user/foo)");
     assert_eq!(name, "foo");
     assert_eq!(version, Version::from_str("0.1.0").unwrap());
-    assert!(!is_git);
-    assert_eq!(path, Some("/home/user/foo".to_string()));
+    assert_eq!(source, Source::Path("/home/user/foo".to_string()));
 
-    let (name, version, is_git, path) = slice_info("foo 0.1.0 (registry+https://example.com/foo)");
+    let (name, version, source) = slice_info("foo 0.1.0 (registry+https://example.com/foo)");
     assert_eq!(name, "foo");
     assert_eq!(version, Version::from_str("0.1.0").unwrap());
-    assert!(!is_git);
-    assert_eq!(path, None);
+    assert_eq!(source, Source::Registry);
 
-    let (name, version, is_git, path) = slice_info("foo 0.1.0 (git+https://github.com/foo/bar#hash)");
+    let (name, version, source) = slice_info("foo 0.1.0 (git+https://github.com/foo/bar#hash)");
     assert_eq!(name, "foo");
     assert_eq!(version, Version::from_str("0.1.0").unwrap());
-    assert!(is_git);
-    assert_eq!(path, None);
+    assert_eq!(
+        source,
+        Source::Git {
+            url: "https://github.com/foo/bar".to_string(),
+            reference: GitRef::Rev("hash".to_string()),
+        }
+    );
+
+    // A `?branch=`/`?tag=`/`?rev=` hint still pins to the locked commit so the
+    // exact backed up revision is reinstalled rather than the branch/tag tip.
+    let (_, _, source) = slice_info("foo 0.1.0 (git+https://github.com/foo/bar?branch=main#abc123)");
+    assert_eq!(
+        source,
+        Source::Git {
+            url: "https://github.com/foo/bar".to_string(),
+            reference: GitRef::Rev("abc123".to_string()),
+        }
+    );
+
+    let (_, _, source) = slice_info("foo 0.1.0 (git+https://github.com/foo/bar?tag=v1#def456)");
+    assert_eq!(
+        source,
+        Source::Git {
+            url: "https://github.com/foo/bar".to_string(),
+            reference: GitRef::Rev("def456".to_string()),
+        }
+    );
+
+    let (_, _, source) = slice_info("foo 0.1.0 (git+https://github.com/foo/bar?rev=short#aa99bb)");
+    assert_eq!(
+        source,
+        Source::Git {
+            url: "https://github.com/foo/bar".to_string(),
+            reference: GitRef::Rev("aa99bb".to_string()),
+        }
+    );
+
+    // With only a named reference and no locked commit, fall back to it.
+    let (_, _, source) = slice_info("foo 0.1.0 (git+https://github.com/foo/bar?branch=main)");
+    assert_eq!(
+        source,
+        Source::Git {
+            url: "https://github.com/foo/bar".to_string(),
+            reference: GitRef::Branch("main".to_string()),
+        }
+    );
 }
 
 #[test]
@@ -251,6 +492,77 @@ fn test_get_packages() {
     assert_eq!(packages.len(), 3);
 }
 
+#[test]
+fn test_get_packages_v1() {
+    // Read on its own, the legacy file lets the v1 reader reconstruct the
+    // packages from its keys and binary lists.
+    let packages = get_packages_v1().expect("v1 fixture should parse");
+    assert_eq!(packages.len(), 3);
+
+    let exa = packages.iter().find(|p| p.name == "exa").unwrap();
+    assert_eq!(exa.version, Version::parse("0.10.1").unwrap());
+    assert_eq!(exa.bins, vec!["exa".to_string()]);
+    // v1 does not record feature or requirement metadata.
+    assert_eq!(exa.version_req, None);
+    assert_eq!(exa.profile, "release");
+}
+
+#[test]
+fn test_merge_packages() {
+    let v2 = vec![Package {
+        name: "exa".to_string(),
+        features: vec!["git".to_string()],
+        all_features: false,
+        no_default_features: false,
+        version: Version::parse("0.10.1").unwrap(),
+        profile: "release".to_string(),
+        target: None,
+        version_req: Some("^0.10".to_string()),
+        // v2 recorded the package but is missing its binary.
+        bins: vec![],
+        source: Source::Registry,
+    }];
+    let v1 = vec![
+        Package {
+            name: "exa".to_string(),
+            features: vec![],
+            all_features: false,
+            no_default_features: false,
+            version: Version::parse("0.10.1").unwrap(),
+            profile: "release".to_string(),
+            target: None,
+            version_req: None,
+            bins: vec!["exa".to_string()],
+            source: Source::Registry,
+        },
+        Package {
+            name: "fd-find".to_string(),
+            features: vec![],
+            all_features: false,
+            no_default_features: false,
+            version: Version::parse("9.0.0").unwrap(),
+            profile: "release".to_string(),
+            target: None,
+            version_req: None,
+            bins: vec!["fd".to_string()],
+            source: Source::Registry,
+        },
+    ];
+
+    let merged = merge_packages(v2, v1);
+    assert_eq!(merged.len(), 2);
+
+    let exa = merged.iter().find(|p| p.name == "exa").unwrap();
+    // v2 wins on metadata...
+    assert_eq!(exa.features, vec!["git".to_string()]);
+    assert_eq!(exa.version_req, Some("^0.10".to_string()));
+    // ...while v1 fills in the binary v2 was missing.
+    assert_eq!(exa.bins, vec!["exa".to_string()]);
+
+    // A package only v1 knows about is carried over.
+    assert!(merged.iter().any(|p| p.name == "fd-find"));
+}
+
 #[test]
 fn test_install_packages() {
     let fake_packages: Vec<Package> = vec![
@@ -264,7 +576,7 @@ fn test_install_packages() {
             target: None,
             version_req: None,
             bins: vec!["foo".to_string()],
-            source_path: None,
+            source: Source::Registry,
         },
         Package {
             name: "package".to_string(),
@@ -276,9 +588,9 @@ fn test_install_packages() {
             target: Some("x86_64-unknown-linux-gnu".to_string()),
             version_req: Some("=0.5.3".to_string()),
             bins: vec!["package".to_string(), "package-subcmd".to_string()],
-            source_path: None,
+            source: Source::Registry,
         },
     ];
 
-    install_packages(&fake_packages, false, false, false, false);
+    install_packages(&fake_packages, false, false, false, false, None, false);
 }
@@ -0,0 +1,138 @@
+use crate::remote::YankBump;
+use crate::{GitRef, Package, Source};
+use owo_colors::OwoColorize;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+/// Errors that can occur while reading or parsing cargo's tracking files.
+pub enum Errors {
+    ReadFile,
+    JsonParse,
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Errors::ReadFile => write!(f, "Failed to read cargo's tracking file"),
+            Errors::JsonParse => write!(f, "Failed to parse cargo's tracking file"),
+        }
+    }
+}
+
+/// The kind of cargo command to run for a package.
+pub enum CommandType {
+    Install,
+    Remove,
+}
+
+/// Builds and runs the cargo command that installs or removes a package.
+///
+/// When `target_dir` is set it is exported as `CARGO_TARGET_DIR` so a bulk
+/// restore can share build artifacts across every install. When `force` is set
+/// `--force` is passed so an existing binary is overwritten.
+pub fn execute_cmd(package: &Package, command: CommandType, target_dir: Option<&Path>, force: bool) {
+    let mut cmd = Command::new("cargo");
+
+    if let Some(dir) = target_dir {
+        cmd.env("CARGO_TARGET_DIR", dir);
+    }
+
+    match command {
+        CommandType::Install => {
+            cmd.arg("install");
+
+            if force {
+                cmd.arg("--force");
+            }
+
+            match &package.source {
+                Source::Registry => {
+                    cmd.arg(&package.name);
+                    // Reinstall under the originally recorded constraint rather
+                    // than pinning to whatever exact version was captured.
+                    if let Some(req) = &package.version_req {
+                        cmd.arg("--version").arg(req);
+                    }
+                }
+                Source::Path(path) => {
+                    cmd.arg("--path").arg(path);
+                }
+                Source::Git { url, reference } => {
+                    cmd.arg("--git").arg(url);
+                    match reference {
+                        GitRef::Rev(rev) => {
+                            cmd.arg("--rev").arg(rev);
+                        }
+                        GitRef::Branch(branch) => {
+                            cmd.arg("--branch").arg(branch);
+                        }
+                        GitRef::Tag(tag) => {
+                            cmd.arg("--tag").arg(tag);
+                        }
+                        GitRef::Default => {}
+                    }
+                }
+            }
+
+            if package.all_features {
+                cmd.arg("--all-features");
+            }
+            if package.no_default_features {
+                cmd.arg("--no-default-features");
+            }
+            if !package.features.is_empty() {
+                cmd.arg("--features").arg(package.features.join(","));
+            }
+            if let Some(target) = &package.target {
+                cmd.arg("--target").arg(target);
+            }
+        }
+        CommandType::Remove => {
+            cmd.arg("uninstall").arg(&package.name);
+        }
+    }
+
+    match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(_) => println!("{} {}", package.name.red(), "failed to install".red()),
+        Err(e) => println!("{} {}", package.name.red(), e.red()),
+    }
+}
+
+/// Prints the set of packages that will be installed, updated, reconfigured and
+/// removed, along with any that were bumped off a yanked version.
+pub fn pretty_print_packages(
+    to_install: &[Package],
+    to_update: &[Package],
+    to_remove: &[Package],
+    to_reconfigure: &[Package],
+    bumped: &[YankBump],
+) {
+    for package in to_install {
+        println!("{} {}", "install".green(), package.name.green());
+    }
+    for package in to_update {
+        println!("{} {}", "update".blue(), package.name.blue());
+    }
+    for package in to_reconfigure {
+        println!(
+            "{} {} {}",
+            "reconfigure".cyan(),
+            package.name.cyan(),
+            "(features/profile/target changed)".cyan()
+        );
+    }
+    for package in to_remove {
+        println!("{} {}", "remove".red(), package.name.red());
+    }
+    for bump in bumped {
+        println!(
+            "{} {} {} -> {}",
+            "bumped".yellow(),
+            bump.name.yellow(),
+            bump.from,
+            bump.to
+        );
+    }
+}
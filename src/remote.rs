@@ -0,0 +1,184 @@
+//! Talking to crates.io when restoring a backup.
+
+use crate::{Package, Source};
+use owo_colors::OwoColorize;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+/// A single line of a crate's sparse-index metadata.
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: Version,
+    yanked: bool,
+}
+
+/// Records a package that was moved off a yanked version during restore.
+pub struct YankBump {
+    pub name: String,
+    pub from: Version,
+    pub to: Version,
+}
+
+/// Builds the sparse-index path (`<prefix>/<name>`) for a crate name.
+///
+/// One- and two-char names live under `1`/`2`, three-char names under
+/// `3/<first-char>`, and everything else under `<first-two>/<next-two>`.
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    let prefix = match name.len() {
+        1 => "1".to_string(),
+        2 => "2".to_string(),
+        3 => format!("3/{}", &name[0..1]),
+        _ => format!("{}/{}", &name[0..2], &name[2..4]),
+    };
+    format!("{prefix}/{name}")
+}
+
+/// Fetches and parses the sparse-index entries for a crate.
+fn fetch_index(name: &str) -> Option<Vec<IndexEntry>> {
+    let url = format!("https://index.crates.io/{}", index_path(name));
+    let body = reqwest::blocking::get(url).ok()?.text().ok()?;
+
+    Some(
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+            .collect(),
+    )
+}
+
+/// Decides whether a recorded version needs to move off a yank.
+///
+/// Returns `None` when the recorded version is not yanked (nothing to do).
+/// Otherwise returns the highest non-yanked version that still satisfies the
+/// recorded requirement; an exact pin (`=x.y.z`) on a now-yanked version leaves
+/// no candidate, so it falls back to the highest non-yanked version overall
+/// rather than letting cargo reject the yanked pin. When every version in the
+/// index is yanked there is no candidate and `None` is returned as well.
+fn select_non_yanked(
+    entries: &[IndexEntry],
+    version: &Version,
+    req: Option<&VersionReq>,
+) -> Option<Version> {
+    let is_yanked = entries
+        .iter()
+        .any(|entry| &entry.vers == version && entry.yanked);
+    if !is_yanked {
+        return None;
+    }
+
+    let highest = |req: Option<&VersionReq>| {
+        entries
+            .iter()
+            .filter(|entry| !entry.yanked)
+            .filter(|entry| req.map(|r| r.matches(&entry.vers)).unwrap_or(true))
+            .map(|entry| &entry.vers)
+            .max()
+            .cloned()
+    };
+
+    highest(req).or_else(|| highest(None))
+}
+
+/// Checks each registry-sourced package against the index and, when its
+/// recorded version has been yanked, rewrites it to the highest non-yanked
+/// version that still satisfies the recorded requirement (or the highest
+/// non-yanked version overall when no requirement was stored or no non-yanked
+/// version satisfies it). Packages with no non-yanked version are left as is.
+///
+/// Returns the packages that were bumped so the caller can summarise them.
+pub fn resolve_yanked(packages: &mut [Package]) -> Vec<YankBump> {
+    let mut bumped = vec![];
+
+    for package in packages.iter_mut() {
+        if !matches!(package.source, Source::Registry) {
+            continue;
+        }
+
+        let Some(entries) = fetch_index(&package.name) else {
+            continue;
+        };
+
+        let req = package
+            .version_req
+            .as_deref()
+            .and_then(|r| VersionReq::parse(r).ok());
+
+        let best = select_non_yanked(&entries, &package.version, req.as_ref());
+
+        if let Some(best) = best {
+            println!(
+                "{} {} ({} is yanked)",
+                package.name.yellow(),
+                "selecting a non-yanked version".yellow(),
+                package.version
+            );
+            bumped.push(YankBump {
+                name: package.name.clone(),
+                from: package.version.clone(),
+                to: best.clone(),
+            });
+            // Pin the install to the resolved version so the generated command
+            // actually installs it instead of the yanked pin.
+            package.version_req = Some(format!("={best}"));
+            package.version = best;
+        }
+    }
+
+    bumped
+}
+
+#[test]
+fn test_index_path() {
+    assert_eq!(index_path("a"), "1/a");
+    assert_eq!(index_path("ab"), "2/ab");
+    assert_eq!(index_path("abc"), "3/a/abc");
+    assert_eq!(index_path("serde"), "se/rd/serde");
+    // Names are lowercased before the prefix is derived.
+    assert_eq!(index_path("Serde"), "se/rd/serde");
+}
+
+#[cfg(test)]
+fn entry(vers: &str, yanked: bool) -> IndexEntry {
+    IndexEntry {
+        vers: Version::parse(vers).unwrap(),
+        yanked,
+    }
+}
+
+#[test]
+fn test_select_non_yanked() {
+    let entries = vec![
+        entry("1.0.0", false),
+        entry("1.1.0", true),
+        entry("1.2.0", false),
+        entry("2.0.0", false),
+    ];
+
+    // Recorded version is not yanked: nothing to do.
+    assert_eq!(
+        select_non_yanked(&entries, &Version::parse("1.0.0").unwrap(), None),
+        None
+    );
+
+    // Recorded version yanked, no requirement: highest non-yanked overall.
+    assert_eq!(
+        select_non_yanked(&entries, &Version::parse("1.1.0").unwrap(), None),
+        Some(Version::parse("2.0.0").unwrap())
+    );
+
+    // Recorded version yanked, requirement present: highest non-yanked that
+    // still satisfies the requirement.
+    let req = VersionReq::parse("^1").unwrap();
+    assert_eq!(
+        select_non_yanked(&entries, &Version::parse("1.1.0").unwrap(), Some(&req)),
+        Some(Version::parse("1.2.0").unwrap())
+    );
+
+    // Exact pin on a yanked version: fall back to the highest overall.
+    let pin = VersionReq::parse("=1.1.0").unwrap();
+    assert_eq!(
+        select_non_yanked(&entries, &Version::parse("1.1.0").unwrap(), Some(&pin)),
+        Some(Version::parse("2.0.0").unwrap())
+    );
+}